@@ -3,10 +3,17 @@
 
 use super::*;
 
-use std::cell::Cell;
+use std::cell::{Cell, RefCell};
 use std::default::Default;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Once;
 use std::time;
 
+use hdrhistogram::serialization::{Deserializer, Serializer, V2Serializer};
+use hdrhistogram::Histogram as HdrHistogram;
+use rayon::prelude::*;
+
 /// A `Plugin` is an object that can be used to configure a MonteCarlo
 /// simulation.  The plugin will be called regularly, and will have a
 /// chance to save data (e.g. collect statistics) and/or terminate the
@@ -32,6 +39,14 @@ pub trait Plugin<MC: MonteCarlo> {
     /// care about.  This is called in response to `Action::Save`,
     /// `Action::Log` and `Action::Exit`.
     fn log(&self, _mc: &MC, _sys: &MC::System) {}
+    /// Called once, after `log`/`save`, when `Action::Exit` is what
+    /// actually fired (as opposed to a plugin merely asking for
+    /// `Action::Log` or `Action::Save`).  Plugins that own background
+    /// resources -- a writer thread, an open connection -- should tear
+    /// them down here rather than relying only on `Drop`, since a clean
+    /// exit shouldn't depend on exactly when (or whether) the plugin
+    /// value itself gets dropped.
+    fn shutdown(&self, _mc: &MC, _sys: &MC::System) {}
 }
 
 /// An action that should be taken based on this plugin's decision.
@@ -54,17 +69,39 @@ impl Action {
 }
 
 /// A helper to enable Monte Carlo implementations to easily run their
-/// plugins without duplicating code.
+/// plugins without duplicating code.  Plain `Cell`s are enough here: a
+/// `PluginManager`'s `run` is only ever called from the single thread
+/// driving a sequential run or an ensemble (see `run_ensemble`'s doc
+/// comment) -- never concurrently from more than one replica thread at
+/// a time -- so there's nothing here that actually needs to be `Sync`.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct PluginManager {
     period: Cell<u64>,
     moves: Cell<u64>,
+    /// Set once some plugin has asked for `Action::Exit`.  We used to
+    /// respond to that by calling `std::process::exit`, but that would
+    /// kill sibling replica threads mid-write; instead callers are
+    /// expected to poll `should_stop` between sweeps and wind down
+    /// cooperatively.
+    #[serde(skip)]
+    should_stop: Cell<bool>,
 }
 
 impl PluginManager {
     /// Create a plugin manager.
     pub fn new() -> PluginManager {
-        PluginManager { period: Cell::new(1), moves: Cell::new(0) }
+        PluginManager {
+            period: Cell::new(1),
+            moves: Cell::new(0),
+            should_stop: Cell::new(false),
+        }
+    }
+    /// Whether some plugin has asked the simulation to stop.  The
+    /// top-level driver (sequential or replica-ensemble) should check
+    /// this between sweeps rather than relying on `run` to terminate
+    /// the process itself.
+    pub fn should_stop(&self) -> bool {
+        self.should_stop.get()
     }
     /// Run all the plugins, if needed.  This should always be called
     /// with the same set of plugins.  If you want different sets of
@@ -91,7 +128,10 @@ impl PluginManager {
                 }
             }
             if todo >= plugin::Action::Exit {
-                ::std::process::exit(0);
+                for p in plugins.iter() {
+                    p.shutdown(mc, sys);
+                }
+                self.should_stop.set(true);
             }
             // run plugins every trillion iterations minimum
             let mut new_period = 1u64 << 40;
@@ -107,15 +147,137 @@ impl PluginManager {
     }
 }
 
+/// How a set of independent Monte Carlo replicas (e.g. parallel
+/// tempering walkers, or just independent seeds run for better
+/// statistics) should be advanced.  Modeled on the `ExecutionMode`
+/// switch in the external nbody demo, so users can A/B single-threaded
+/// against rayon-parallel throughput without changing anything else.
+#[derive(ClapMe, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ExecutionMode {
+    /// Advance each replica in turn, on a single thread.
+    Sequential,
+    /// Advance every replica at once via `rayon`'s `par_iter_mut`.
+    ParReplicas,
+}
+
+impl Default for ExecutionMode {
+    fn default() -> Self { ExecutionMode::Sequential }
+}
+
+/// Run a single `MC`/`System` pair under `plugins` until some plugin
+/// requests `Action::Exit`.  This is the plain, non-ensemble counterpart
+/// to `run_ensemble`: since `PluginManager::run` no longer calls
+/// `std::process::exit` itself (see `should_stop`), any driver loop that
+/// used to rely on that hard exit to terminate -- including the ordinary
+/// single-replica case -- needs to go through a loop like this one
+/// instead.  No extra cleanup pass is needed after the loop:
+/// `PluginManager::run` already runs every plugin's `log`/`save`/
+/// `shutdown` for the very tick that set `Action::Exit`, so doing it
+/// again here would just duplicate that output (a second `Report`
+/// printout, a second Influx point, ...).
+pub fn run_sequential<MC, F>(manager: &PluginManager,
+                             plugins: &[&Plugin<MC>],
+                             mc: &mut MC,
+                             sys: &mut MC::System,
+                             sweep: F)
+    where MC: MonteCarlo,
+          F: Fn(&mut MC, &mut MC::System),
+{
+    while !manager.should_stop() {
+        sweep(mc, sys);
+        manager.run(mc, sys, plugins);
+    }
+}
+
+/// Run an ensemble of independent replicas, each paired with its own
+/// `MC::System`, sharing one `PluginManager` and one set of `plugins` so
+/// statistics are judged across the whole ensemble rather than per
+/// replica.  Each sweep advances every replica (sequentially, or all at
+/// once according to `mode`), then the plugins are run once per replica,
+/// on the same thread that called `run_ensemble`.  Once a plugin
+/// requests `Action::Exit`, the replicas are left to finish their
+/// current sweep and the loop stops; no extra cleanup pass is needed
+/// (see `run_sequential`), since `PluginManager::run` already ran every
+/// plugin's `log`/`save`/`shutdown` for the replica whose tick set
+/// `Action::Exit`.
+///
+/// Only `sweep` ever runs on more than one thread at a time -- with
+/// `ExecutionMode::ParReplicas`, `rayon` fans it out across replicas --
+/// so only `sweep`'s closure needs to be `Sync`.  `manager.run` and every
+/// plugin are always invoked one at a time, in the `for` loop below, on
+/// whichever single thread is driving the ensemble; that's why
+/// `PluginManager` and the plugins in this file get away with plain
+/// `Cell`/`RefCell` rather than needing to be `Sync` themselves.
+pub fn run_ensemble<MC, F>(mode: ExecutionMode,
+                           manager: &PluginManager,
+                           plugins: &[&Plugin<MC>],
+                           replicas: &mut [(MC, MC::System)],
+                           sweep: F)
+    where MC: MonteCarlo + Send,
+          MC::System: Send,
+          F: Fn(&mut MC, &mut MC::System) + Sync,
+{
+    while !manager.should_stop() {
+        match mode {
+            ExecutionMode::Sequential => {
+                for (mc, sys) in replicas.iter_mut() {
+                    sweep(mc, sys);
+                }
+            }
+            ExecutionMode::ParReplicas => {
+                replicas.par_iter_mut().for_each(|(mc, sys)| sweep(mc, sys));
+            }
+        }
+        for (mc, sys) in replicas.iter() {
+            manager.run(mc, sys, plugins);
+            if manager.should_stop() {
+                break;
+            }
+        }
+    }
+}
+
 fn no_time() -> Cell<Option<(time::Instant, u64)>> { Cell::new(None) }
 
-/// A plugin that terminates the simulation after a fixed number of iterations.
+/// Set once a SIGINT arrives, so `Report` can turn a `ctrl-C` into a
+/// clean `Action::Save.and(Action::Exit)` rather than losing the run to
+/// a hard kill.
+static SIGNAL_CAUGHT: AtomicBool = AtomicBool::new(false);
+
+/// Installs the SIGINT (`ctrl-C`) handler the first time a `Report` is
+/// created.  Signal handlers are process-global, so there is no point
+/// (and no way) to install more than one.  `ctrlc` is built without its
+/// `termination` feature, so SIGTERM is not caught.
+fn install_signal_handler() {
+    static INSTALLED: Once = Once::new();
+    INSTALLED.call_once(|| {
+        let _ = ctrlc::set_handler(|| SIGNAL_CAUGHT.store(true, Ordering::SeqCst));
+    });
+}
+
+/// A plugin that terminates the simulation after a fixed number of
+/// iterations, a fixed amount of wall-clock time, or a SIGINT.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Report {
     max_iter: Option<u64>,
+    /// Stop (and save) once this much wall-clock time has elapsed.
+    max_time: Option<time::Duration>,
+    /// Checkpoint after each fraction of `max_time` that passes, e.g. a
+    /// value of `0.1` checkpoints at 10%, 20%, ... of the deadline, so a
+    /// run that is killed near the end still has a recent save.
+    max_time_fraction_save: f64,
     /// This is when and where the simulation started.
     #[serde(skip, default="no_time")]
     start: Cell<Option<(time::Instant, u64)>>,
+    /// The move count as of the most recent `run`, used to estimate
+    /// moves-per-second for `run_period`.  A plain `Cell` is enough: a
+    /// `Report`'s `run`/`run_period` are only ever called from the
+    /// single thread driving a run (see `run_ensemble`'s doc comment).
+    #[serde(skip)]
+    last_moves: Cell<u64>,
+    /// The next fraction of `max_time` at which we should checkpoint.
+    #[serde(skip)]
+    next_time_fraction: Cell<f64>,
 }
 
 /// The parameter to define the maximum number of iterations.
@@ -123,21 +285,36 @@ pub struct Report {
 pub struct ReportParams {
     /// The maximum number of iterations to run.
     pub max_iter: Option<u64>,
+    /// The maximum amount of wall-clock time to run, e.g. `2h30m`.
+    pub max_time: Option<humantime::Duration>,
+    /// Checkpoint after each this fraction of `max_time` has elapsed.
+    /// Defaults to checkpointing every 10% of `max_time`.
+    pub max_time_fraction_save: Option<f64>,
 }
 
 impl Default for ReportParams {
     fn default() -> Self {
         ReportParams {
             max_iter: None,
+            max_time: None,
+            max_time_fraction_save: None,
         }
     }
 }
 
 impl From<ReportParams> for Report {
     fn from(params: ReportParams) -> Self {
+        install_signal_handler();
+        let max_time_fraction_save = params.max_time_fraction_save.unwrap_or(0.1);
+        assert!(max_time_fraction_save > 0.0,
+                "max_time_fraction_save must be positive, got {}", max_time_fraction_save);
         Report {
             max_iter: params.max_iter,
+            max_time: params.max_time.map(|d| *d),
+            max_time_fraction_save,
             start: Cell::new(Some((time::Instant::now(), 0))),
+            last_moves: Cell::new(0),
+            next_time_fraction: Cell::new(max_time_fraction_save),
         }
     }
 }
@@ -146,14 +323,64 @@ fn duration_from_f64(seconds: f64) -> time::Duration {
 }
 impl<MC: MonteCarlo> Plugin<MC> for Report {
     fn run(&self, mc: &MC, _sys: &MC::System) -> Action {
+        self.last_moves.set(mc.num_moves());
+        if self.start.get().is_none() {
+            // Either the very first call, or we've just been restored
+            // from a checkpoint: `start` and `next_time_fraction` are
+            // both `#[serde(skip)]`, so re-seed them here rather than
+            // silently running with a dead `max_time` clock (or firing
+            // a spurious immediate `Save` from a `next_time_fraction`
+            // that deserialized to `0.0`).
+            self.start.set(Some((time::Instant::now(), mc.num_moves())));
+            self.next_time_fraction.set(self.max_time_fraction_save);
+        }
+        if SIGNAL_CAUGHT.load(Ordering::SeqCst) {
+            return Action::Save.and(Action::Exit);
+        }
         if let Some(maxiter) = self.max_iter {
             if mc.num_moves() >= maxiter {
                 return Action::Exit;
             }
         }
+        if let (Some(max_time), Some((start_time, _))) = (self.max_time, self.start.get()) {
+            let elapsed = start_time.elapsed();
+            if elapsed >= max_time {
+                return Action::Exit;
+            }
+            let frac = elapsed.as_secs_f64()/max_time.as_secs_f64();
+            if frac >= self.next_time_fraction.get() {
+                self.next_time_fraction.set(self.next_time_fraction.get() + self.max_time_fraction_save);
+                return Action::Save;
+            }
+        }
         Action::None
     }
-    fn run_period(&self) -> Option<u64> { self.max_iter }
+    fn run_period(&self) -> Option<u64> {
+        if self.max_time.is_none() {
+            return self.max_iter;
+        }
+        // We can't check the wall clock on every move without paying
+        // for a syscall per move, so estimate moves-per-second from
+        // what we've seen so far and aim to wake up roughly once a
+        // second -- often enough to honor `max_time` and the signal
+        // handler promptly without looking at the clock constantly.
+        let one_second_of_moves = match self.start.get() {
+            Some((start_time, start_iter)) => {
+                let elapsed = start_time.elapsed().as_secs_f64();
+                let moves = self.last_moves.get();
+                if elapsed > 0.0 && moves > start_iter {
+                    (((moves - start_iter) as f64/elapsed) as u64).max(1)
+                } else {
+                    1
+                }
+            }
+            None => 1,
+        };
+        match self.max_iter {
+            Some(max_iter) => Some(max_iter.min(one_second_of_moves)),
+            None => Some(one_second_of_moves),
+        }
+    }
     fn log(&self, mc: &MC, _sys: &MC::System) {
         match self.start.get() {
             Some((start_time, start_iter)) => {
@@ -192,7 +419,9 @@ impl<MC: MonteCarlo> Plugin<MC> for Report {
 }
 
 
-/// A plugin that schedules when to save
+/// A plugin that schedules when to save.  Plain `Cell`s are enough
+/// here: a `Save`'s `run`/`run_period` are only ever called from the
+/// single thread driving a run (see `run_ensemble`'s doc comment).
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Save {
     next_output: Cell<u64>,
@@ -231,3 +460,635 @@ impl<MC: MonteCarlo> Plugin<MC> for Save {
         Some(self.next_output.get())
     }
 }
+
+/// Background state for `InfluxReport`: the channel used to hand points
+/// to the writer thread, and the thread's handle.  Both are wrapped in
+/// `Option` so `Drop` can take them out in the right order: closing the
+/// channel before joining, so the writer thread actually wakes up.
+#[derive(Debug)]
+struct InfluxWriter {
+    sender: Option<crossbeam_channel::Sender<String>>,
+    thread: Option<::std::thread::JoinHandle<()>>,
+}
+
+impl Drop for InfluxWriter {
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn no_influx_writer() -> RefCell<Option<InfluxWriter>> { RefCell::new(None) }
+
+/// Cap on points buffered between the Monte Carlo loop and the writer
+/// thread.  Bounded (rather than unbounded) so that a stalled or slow
+/// server applies backpressure -- `Sender::send` blocks once this many
+/// points are queued -- instead of letting memory grow without limit.
+const INFLUX_CHANNEL_CAPACITY: usize = 1024;
+
+fn start_influx_writer(url: String, db: String) -> InfluxWriter {
+    let (sender, receiver) = crossbeam_channel::bounded::<String>(INFLUX_CHANNEL_CAPACITY);
+    let endpoint = format!("{}/write?db={}", url, db);
+    let thread = ::std::thread::spawn(move || {
+        let agent = ureq::agent();
+        // Block for the first point so we don't busy-loop, then grab
+        // whatever else has piled up so we post in batches rather than
+        // one point per HTTP request.
+        while let Ok(first) = receiver.recv() {
+            let mut batch = first;
+            while let Ok(line) = receiver.try_recv() {
+                batch.push('\n');
+                batch.push_str(&line);
+            }
+            if let Err(e) = agent.post(&endpoint).send_string(&batch) {
+                eprintln!("error writing to influxdb at {}: {}", endpoint, e);
+            }
+        }
+    });
+    InfluxWriter { sender: Some(sender), thread: Some(thread) }
+}
+
+/// A plugin that streams simulation observables to an InfluxDB-compatible
+/// time-series database as line-protocol points, so a running simulation
+/// can be watched live on a dashboard instead of only read back after the
+/// fact.  Points are buffered into a channel and flushed from a
+/// background thread so the Monte Carlo loop doesn't wait on network
+/// I/O; it only blocks once `INFLUX_CHANNEL_CAPACITY` points are queued.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct InfluxReport {
+    /// Base URL of the server, e.g. `http://host:8086`.
+    url: String,
+    /// Database to write points into.
+    db: String,
+    /// Extra tags attached to every point, already formatted as
+    /// `key=value,key=value`.
+    tags: String,
+    /// A tag distinguishing this run from other runs of the same
+    /// algorithm, so points from concurrent runs don't get mixed in a
+    /// dashboard query.
+    run_id: String,
+    #[serde(skip, default = "no_influx_writer")]
+    writer: RefCell<Option<InfluxWriter>>,
+}
+
+/// The parameters to configure where `InfluxReport` sends its points.
+#[derive(ClapMe, Debug)]
+pub struct InfluxReportParams {
+    /// Base URL of the InfluxDB-compatible server, e.g. `http://localhost:8086`.
+    pub influx_url: Option<String>,
+    /// Database to write points into.
+    pub influx_db: Option<String>,
+    /// Extra tags to attach to every point, formatted as `key=value,key=value`.
+    pub influx_tags: Option<String>,
+}
+
+impl Default for InfluxReportParams {
+    fn default() -> Self {
+        InfluxReportParams {
+            influx_url: None,
+            influx_db: None,
+            influx_tags: None,
+        }
+    }
+}
+
+impl From<InfluxReportParams> for InfluxReport {
+    fn from(params: InfluxReportParams) -> Self {
+        InfluxReport {
+            url: params.influx_url.unwrap_or_else(|| "http://localhost:8086".to_string()),
+            db: params.influx_db.unwrap_or_else(|| "sad-monte-carlo".to_string()),
+            tags: params.influx_tags.unwrap_or_default(),
+            run_id: format!("{:x}", ::rand::random::<u64>()),
+            writer: no_influx_writer(),
+        }
+    }
+}
+
+/// Escape a value for use in an InfluxDB line-protocol tag.  Unescaped
+/// commas, spaces and equals signs are syntax in the line protocol, so a
+/// raw `type_name::<MC>()` (which routinely contains all three, plus
+/// `<>`) would otherwise corrupt the line and get the whole batch
+/// rejected by the server.
+fn escape_influx_tag_value(value: &str) -> String {
+    value.replace('\\', "\\\\")
+         .replace(',', "\\,")
+         .replace('=', "\\=")
+         .replace(' ', "\\ ")
+}
+
+impl InfluxReport {
+    fn send_point<MC: MonteCarlo>(&self, mc: &MC, sys: &MC::System) where MC::System: ScalarEnergy {
+        let mut writer = self.writer.borrow_mut();
+        if writer.is_none() {
+            *writer = Some(start_influx_writer(self.url.clone(), self.db.clone()));
+        }
+        let moves = mc.num_moves();
+        let rejects = mc.num_rejected_moves();
+        let rejection_fraction = if moves > 0 { rejects as f64/moves as f64 } else { 0.0 };
+        let timestamp_ns = ::std::time::SystemTime::now()
+            .duration_since(::std::time::UNIX_EPOCH)
+            .expect("system clock is before the unix epoch")
+            .as_nanos();
+        let tags = if self.tags.is_empty() {
+            format!("run_id={}", self.run_id)
+        } else {
+            format!("run_id={},{}", self.run_id, self.tags)
+        };
+        let line = format!(
+            "monte_carlo,algorithm={},{} num_moves={}i,rejection_fraction={},energy={} {}",
+            escape_influx_tag_value(::std::any::type_name::<MC>()),
+            tags,
+            moves,
+            rejection_fraction,
+            sys.scalar_energy(),
+            timestamp_ns,
+        );
+        if let Some(sender) = writer.as_ref().and_then(|w| w.sender.as_ref()) {
+            let _ = sender.send(line);
+        }
+    }
+}
+
+impl<MC: MonteCarlo> Plugin<MC> for InfluxReport where MC::System: ScalarEnergy {
+    fn run(&self, mc: &MC, sys: &MC::System) -> Action {
+        // Emit from `run` (not just `log`/`save`) so the `run_period`
+        // below actually drives a live stream, instead of only firing
+        // when some other plugin happens to request Log or Save.
+        self.send_point(mc, sys);
+        Action::None
+    }
+    fn run_period(&self) -> Option<u64> { Some(1000) }
+    fn log(&self, mc: &MC, sys: &MC::System) {
+        self.send_point(mc, sys);
+    }
+    fn save(&self, mc: &MC, sys: &MC::System) {
+        self.send_point(mc, sys);
+    }
+    fn shutdown(&self, mc: &MC, sys: &MC::System) {
+        // `Action::Exit` means the simulation is actually about to end,
+        // as opposed to just hitting a periodic `Save` -- flush whatever
+        // is still buffered and then close the channel and join the
+        // writer thread, so we know the points made it out (or were at
+        // least attempted) before the process exits. Relying solely on
+        // `InfluxWriter::Drop` would only run this if `InfluxReport`
+        // itself gets dropped cleanly, which a panic or early return
+        // could skip.
+        self.send_point(mc, sys);
+        self.writer.borrow_mut().take();
+    }
+}
+
+/// A system whose energy can be read out as a plain `f64`.  `MC::System`
+/// is not required (by anything in this tree) to expose an `energy()`
+/// method at all, and real implementations elsewhere in this codebase
+/// return a dimensioned `Energy` newtype rather than a bare float, so
+/// `ObservableHistogram` is bounded on this small adapter trait instead
+/// of assuming a method/return-type that isn't part of the reviewable
+/// `Plugin`/`MonteCarlo` interface.
+pub trait ScalarEnergy {
+    /// The system's current energy, as a plain number. Callers are
+    /// expected to be consistent about what units that number is in.
+    fn scalar_energy(&self) -> f64;
+}
+
+/// Which observable an `ObservableHistogram` plugin should track.  There
+/// is deliberately no `Magnetization` variant here: magnetization isn't a
+/// concept every `System` has (a fluid or particle system has none), so
+/// it can't be offered as a generic option the way energy (via
+/// `ScalarEnergy`) can.
+#[derive(ClapMe, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum HistogramObservable {
+    /// The current energy of the system.
+    Energy,
+    /// Wall-clock time between successive moves, in nanoseconds.
+    MoveLatency,
+}
+
+impl Default for HistogramObservable {
+    fn default() -> Self { HistogramObservable::Energy }
+}
+
+/// A plugin that accumulates a streaming, log-linear HDR histogram of a
+/// chosen observable, so we can report percentiles and tail behavior in
+/// bounded memory no matter how many samples are recorded.  Since HDR
+/// histograms only bucket non-negative integers, observed values are
+/// scaled and offset onto the integer line before being recorded; both
+/// are configurable for observables (like energy) that are negative or
+/// fractional in their natural units.
+pub struct ObservableHistogram {
+    observable: HistogramObservable,
+    scale: f64,
+    /// Shifts a scaled observable onto the non-negative integer line
+    /// HDR histograms require.  `None` until the first sample, at which
+    /// point -- unless the user passed `--histogram-offset` explicitly
+    /// -- it is seeded from that sample, so a default `Energy` histogram
+    /// (whose values are routinely negative) doesn't silently collapse
+    /// into bucket zero.
+    offset: Cell<Option<i64>>,
+    /// Whether we've already warned about a sample landing below
+    /// `offset`; we only want to say this once per plugin, not once per
+    /// sample.
+    warned_out_of_range: Cell<bool>,
+    /// Where to serialize the histogram on `save`, if anywhere.
+    path: Option<PathBuf>,
+    histogram: RefCell<HdrHistogram<u64>>,
+    /// Used only by `HistogramObservable::MoveLatency` to time the gap
+    /// between successive calls to `run`.
+    last_move: Cell<Option<time::Instant>>,
+}
+
+impl ::std::fmt::Debug for ObservableHistogram {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_struct("ObservableHistogram")
+            .field("observable", &self.observable)
+            .field("scale", &self.scale)
+            .field("offset", &self.offset.get())
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+/// The parameters to configure an `ObservableHistogram` plugin.
+#[derive(ClapMe, Debug)]
+pub struct ObservableHistogramParams {
+    /// Which observable to accumulate a distribution of.
+    pub histogram_observable: Option<HistogramObservable>,
+    /// Number of significant decimal digits of precision to preserve in
+    /// the histogram, trading memory for percentile accuracy.
+    pub histogram_sigfigs: Option<u8>,
+    /// Scale applied to the observable before it is rounded to the
+    /// nearest integer bucket.
+    pub histogram_scale: Option<f64>,
+    /// Added after scaling, so an observable that can go negative still
+    /// lands on a non-negative bucket.
+    pub histogram_offset: Option<i64>,
+    /// Where to serialize the histogram on `save`.  If the file already
+    /// exists, it is loaded and accumulation resumes from it, so the
+    /// distribution survives (and merges across) a restart.
+    pub histogram_file: Option<PathBuf>,
+}
+
+impl Default for ObservableHistogramParams {
+    fn default() -> Self {
+        ObservableHistogramParams {
+            histogram_observable: None,
+            histogram_sigfigs: None,
+            histogram_scale: None,
+            histogram_offset: None,
+            histogram_file: None,
+        }
+    }
+}
+
+impl From<ObservableHistogramParams> for ObservableHistogram {
+    fn from(params: ObservableHistogramParams) -> Self {
+        let sigfigs = params.histogram_sigfigs.unwrap_or(3);
+        let path = params.histogram_file;
+        let histogram = path.as_ref()
+            .and_then(|p| ::std::fs::read(p).ok())
+            .and_then(|bytes| Deserializer::new().deserialize(&mut &bytes[..]).ok())
+            .unwrap_or_else(|| HdrHistogram::new(sigfigs)
+                             .expect("histogram_sigfigs must be between 0 and 5"));
+        ObservableHistogram {
+            observable: params.histogram_observable.unwrap_or_default(),
+            scale: params.histogram_scale.unwrap_or(1.0),
+            offset: Cell::new(params.histogram_offset),
+            warned_out_of_range: Cell::new(false),
+            path,
+            histogram: RefCell::new(histogram),
+            last_move: Cell::new(None),
+        }
+    }
+}
+
+impl ObservableHistogram {
+    fn record(&self, value: f64) {
+        let offset = match self.offset.get() {
+            Some(offset) => offset,
+            None => {
+                // Seed from the first sample so the default (no
+                // `--histogram-offset`) case doesn't clamp every
+                // negative energy into bucket zero.
+                let seeded = -(value*self.scale).floor() as i64;
+                self.offset.set(Some(seeded));
+                seeded
+            }
+        };
+        let scaled = (value*self.scale).round() as i64 + offset;
+        if scaled < 0 {
+            if !self.warned_out_of_range.replace(true) {
+                eprintln!(
+                    "warning: {:?} value {} is below this histogram's offset ({}); \
+                     clamping into the lowest bucket (pass a larger --histogram-offset \
+                     to avoid this)",
+                    self.observable, value, offset,
+                );
+            }
+            let _ = self.histogram.borrow_mut().record(0);
+            return;
+        }
+        let _ = self.histogram.borrow_mut().record(scaled as u64);
+    }
+    fn unscale(&self, bucket: f64) -> f64 {
+        (bucket - self.offset.get().unwrap_or(0) as f64)/self.scale
+    }
+}
+
+impl<MC: MonteCarlo> Plugin<MC> for ObservableHistogram where MC::System: ScalarEnergy {
+    fn run(&self, _mc: &MC, sys: &MC::System) -> Action {
+        let value = match self.observable {
+            HistogramObservable::Energy => sys.scalar_energy(),
+            HistogramObservable::MoveLatency => {
+                let now = time::Instant::now();
+                match self.last_move.replace(Some(now)) {
+                    Some(previous) => now.duration_since(previous).as_nanos() as f64,
+                    None => 0.0,
+                }
+            }
+        };
+        self.record(value);
+        Action::None
+    }
+    fn run_period(&self) -> Option<u64> { Some(1) }
+    fn log(&self, _mc: &MC, _sys: &MC::System) {
+        let h = self.histogram.borrow();
+        if h.len() == 0 {
+            return;
+        }
+        println!(
+            "{:?}: p50={:.6} p90={:.6} p99={:.6} max={:.6} mean={:.6} stddev={:.6} (n={})",
+            self.observable,
+            self.unscale(h.value_at_quantile(0.5) as f64),
+            self.unscale(h.value_at_quantile(0.9) as f64),
+            self.unscale(h.value_at_quantile(0.99) as f64),
+            self.unscale(h.max() as f64),
+            self.unscale(h.mean()),
+            h.stdev()/self.scale,
+            h.len(),
+        );
+    }
+    fn save(&self, _mc: &MC, _sys: &MC::System) {
+        if let Some(path) = &self.path {
+            let h = self.histogram.borrow();
+            let mut buf = Vec::new();
+            if let Err(e) = V2Serializer::new().serialize(&h, &mut buf) {
+                eprintln!("error serializing histogram: {:?}", e);
+                return;
+            }
+            if let Err(e) = ::std::fs::write(path, &buf) {
+                eprintln!("error writing histogram to {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// Which save/log cadence a `Schedule` plugin should follow.
+#[derive(ClapMe, Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SchedulePolicy {
+    /// Double the interval between saves each time (the historical
+    /// behavior of the old `Save` plugin).
+    Geometric,
+    /// Save every fixed number of moves.
+    FixedMoves,
+    /// Save every fixed wall-clock interval.
+    FixedTime,
+    /// Save at an explicit, one-shot list of move counts and nothing
+    /// else.
+    At,
+}
+
+impl Default for SchedulePolicy {
+    fn default() -> Self { SchedulePolicy::Geometric }
+}
+
+/// A general-purpose replacement for `Save`'s hard-coded doubling
+/// cadence.  Pending `(trigger, Action::Save)` entries are kept in
+/// `pending_moves` (for the move-counting policies) or tracked as a
+/// single wall-clock deadline (for `FixedTime`); `run` pops whichever
+/// triggers have passed and, for the recurring policies, re-inserts the
+/// next one.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Schedule {
+    policy: SchedulePolicy,
+    /// Pending move-count triggers for `Geometric`, `FixedMoves`, and
+    /// `At`.  Kept as a small unsorted list rather than a proper heap:
+    /// in practice there are rarely more than a handful of entries
+    /// pending at once, so a linear scan for the minimum is plenty fast.
+    pending_moves: RefCell<Vec<u64>>,
+    /// The first trigger for `Geometric` and `FixedMoves`.  `FixedMoves`
+    /// also adds it to each trigger that fires to get the next one;
+    /// `Geometric` doubles the fired trigger instead.  Unused for `At`.
+    moves_interval: Cell<u64>,
+    /// The wall-clock interval between saves, for `FixedTime`.
+    time_interval: time::Duration,
+    /// When and where (in moves) we started, so `FixedTime` can measure
+    /// elapsed time and estimate moves-per-second for `run_period`.
+    #[serde(skip, default="no_time")]
+    start: Cell<Option<(time::Instant, u64)>>,
+    /// The move count as of the most recent `run`, used alongside
+    /// `start` to estimate moves-per-second for `run_period`.
+    #[serde(skip)]
+    last_moves: Cell<u64>,
+    /// The next wall-clock deadline, for `FixedTime`.
+    next_time_trigger: Cell<time::Duration>,
+}
+
+/// The parameters to configure a `Schedule` plugin.
+#[derive(ClapMe, Debug)]
+pub struct ScheduleParams {
+    /// Which cadence policy to use.
+    pub schedule_policy: Option<SchedulePolicy>,
+    /// For `FixedMoves`, how many moves between saves.
+    pub schedule_moves_interval: Option<u64>,
+    /// For `FixedTime`, how much wall-clock time between saves, e.g. `10m`.
+    pub schedule_time_interval: Option<humantime::Duration>,
+    /// For `At`, the explicit move counts to checkpoint at.
+    pub schedule_at: Option<Vec<u64>>,
+}
+
+impl Default for ScheduleParams {
+    fn default() -> Self {
+        ScheduleParams {
+            schedule_policy: None,
+            schedule_moves_interval: None,
+            schedule_time_interval: None,
+            schedule_at: None,
+        }
+    }
+}
+
+impl From<ScheduleParams> for Schedule {
+    fn from(params: ScheduleParams) -> Self {
+        let policy = params.schedule_policy.unwrap_or_default();
+        let moves_interval = params.schedule_moves_interval.unwrap_or(1);
+        let time_interval = params.schedule_time_interval.map(|d| *d)
+            .unwrap_or_else(|| time::Duration::new(3600, 0));
+        let pending_moves = match policy {
+            SchedulePolicy::Geometric | SchedulePolicy::FixedMoves => vec![moves_interval],
+            SchedulePolicy::At => params.schedule_at.unwrap_or_default(),
+            SchedulePolicy::FixedTime => Vec::new(),
+        };
+        Schedule {
+            policy,
+            pending_moves: RefCell::new(pending_moves),
+            moves_interval: Cell::new(moves_interval),
+            time_interval,
+            start: Cell::new(None),
+            last_moves: Cell::new(0),
+            next_time_trigger: Cell::new(time_interval),
+        }
+    }
+}
+
+impl<MC: MonteCarlo> Plugin<MC> for Schedule {
+    fn run(&self, mc: &MC, _sys: &MC::System) -> Action {
+        let moves = mc.num_moves();
+        if self.start.get().is_none() {
+            self.start.set(Some((time::Instant::now(), moves)));
+        }
+        self.last_moves.set(moves);
+        match self.policy {
+            SchedulePolicy::FixedTime => {
+                let (start_time, _) = self.start.get().expect("just set above");
+                if start_time.elapsed() >= self.next_time_trigger.get() {
+                    self.next_time_trigger.set(self.next_time_trigger.get() + self.time_interval);
+                    Action::Save
+                } else {
+                    Action::None
+                }
+            }
+            SchedulePolicy::Geometric | SchedulePolicy::FixedMoves | SchedulePolicy::At => {
+                let mut pending = self.pending_moves.borrow_mut();
+                let mut fired = false;
+                loop {
+                    let due = pending.iter().cloned().enumerate()
+                        .filter(|&(_, trigger)| trigger <= moves)
+                        .min_by_key(|&(_, trigger)| trigger);
+                    match due {
+                        Some((i, trigger)) => {
+                            pending.swap_remove(i);
+                            fired = true;
+                            match self.policy {
+                                SchedulePolicy::Geometric => {
+                                    // NOTE: this is *not* bit-for-bit what
+                                    // the old `Save` plugin produced.
+                                    // `Save::run_period` returned its
+                                    // absolute `next_output`, but
+                                    // `PluginManager` treats a plugin's
+                                    // `run_period` as a *relative*
+                                    // distance from the current tick, so
+                                    // each doubling compounded with the
+                                    // wait that preceded it and `Save`
+                                    // actually fired at 2^n - 1 (1, 3, 7,
+                                    // 15, ...), not at powers of two. That
+                                    // drift was itself a bug: `Schedule`
+                                    // fires at the absolute powers of two
+                                    // instead (1, 2, 4, 8, 16, ...), and
+                                    // its `run_period` below correctly
+                                    // converts back to a relative
+                                    // distance so the cadence holds.
+                                    pending.push(trigger*2);
+                                }
+                                SchedulePolicy::FixedMoves => {
+                                    pending.push(trigger + self.moves_interval.get());
+                                }
+                                SchedulePolicy::At | SchedulePolicy::FixedTime => {}
+                            }
+                        }
+                        None => break,
+                    }
+                }
+                if fired { Action::Save } else { Action::None }
+            }
+        }
+    }
+    fn run_period(&self) -> Option<u64> {
+        match self.policy {
+            SchedulePolicy::FixedTime => {
+                // We can't check the wall clock on every move, so
+                // estimate moves-per-second from what we've seen so far
+                // and ask to be woken roughly when the deadline is due.
+                match self.start.get() {
+                    Some((start_time, start_moves)) => {
+                        let elapsed = start_time.elapsed().as_secs_f64();
+                        let moves_so_far = self.last_moves.get().saturating_sub(start_moves);
+                        if elapsed > 0.0 && moves_so_far > 0 {
+                            let remaining = self.next_time_trigger.get().as_secs_f64() - elapsed;
+                            let rate = moves_so_far as f64/elapsed;
+                            Some(((remaining.max(0.0)*rate) as u64).max(1))
+                        } else {
+                            Some(1)
+                        }
+                    }
+                    None => Some(1),
+                }
+            }
+            SchedulePolicy::Geometric | SchedulePolicy::FixedMoves | SchedulePolicy::At => {
+                // `PluginManager` treats this as a distance from its own
+                // tick counter (which it resets to 0 after every fire),
+                // not as an absolute move count, so we have to subtract
+                // off how far along we already are.
+                let moves = self.last_moves.get();
+                self.pending_moves.borrow().iter().cloned().min()
+                    .map(|trigger| trigger.saturating_sub(moves).max(1))
+            }
+        }
+    }
+}
+
+/// Every plugin added in this series, bundled into a single `ClapMe`
+/// struct.  This is the one flat set of flags a driver's CLI parser
+/// needs to expose, and `AllPlugins::new` is the one call it needs to
+/// turn the parsed result into the live plugins `PluginManager::run`
+/// (via `run_sequential`/`run_ensemble`) expects.
+#[derive(ClapMe, Debug)]
+pub struct AllPluginsParams {
+    pub report: ReportParams,
+    pub schedule: ScheduleParams,
+    pub influx: InfluxReportParams,
+    pub histogram: ObservableHistogramParams,
+}
+
+impl Default for AllPluginsParams {
+    fn default() -> Self {
+        AllPluginsParams {
+            report: ReportParams::default(),
+            schedule: ScheduleParams::default(),
+            influx: InfluxReportParams::default(),
+            histogram: ObservableHistogramParams::default(),
+        }
+    }
+}
+
+/// The live plugins built from `AllPluginsParams`.  Holding them here
+/// (rather than constructing four locals in `main`) is what lets a
+/// driver build `&[&Plugin<MC>]` with `as_plugins` in one line.
+#[derive(Debug)]
+pub struct AllPlugins {
+    pub report: Report,
+    pub schedule: Schedule,
+    pub influx: InfluxReport,
+    pub histogram: ObservableHistogram,
+}
+
+impl From<AllPluginsParams> for AllPlugins {
+    fn from(params: AllPluginsParams) -> Self {
+        AllPlugins {
+            report: params.report.into(),
+            schedule: params.schedule.into(),
+            influx: params.influx.into(),
+            histogram: params.histogram.into(),
+        }
+    }
+}
+
+impl AllPlugins {
+    /// The slice `PluginManager::run`, `run_sequential`, and
+    /// `run_ensemble` all expect.  Bounded on `MC::System: ScalarEnergy`
+    /// because `ObservableHistogram`'s `Plugin<MC>` impl requires it.
+    pub fn as_plugins<MC: MonteCarlo>(&self) -> Vec<&Plugin<MC>> where MC::System: ScalarEnergy {
+        vec![&self.report, &self.schedule, &self.influx, &self.histogram]
+    }
+}